@@ -1,36 +1,96 @@
 use hyper::{
     body::Bytes,
+    client::{Client, HttpConnector},
     service::{make_service_fn, service_fn},
     Method, StatusCode,
 };
 use hyper::{Body, Request, Response, Server};
+use membership::{peers_request, Peers};
+use metrics::Metrics;
 use serde::Deserialize;
-use shared_db::{fetch_request, info_request, EntryModif, SharedDB};
+use shared_db::{
+    fetch_stream_request, info_request, merkle_reconcile, watch_request, EntryModif, SharedDB,
+};
 use std::net::SocketAddr;
 use std::{convert::Infallible, time::Duration};
+mod membership;
+mod merkle;
+mod metrics;
+mod ndjson;
 mod shared_db;
+mod store;
 
-pub const BOOTSTRAP_FETCH_PERIOD: Duration = Duration::from_secs(1);
+/// How long a `/watch` request parks waiting for a new head before
+/// returning with an empty diff, letting the client retry.
+pub const WATCH_TIMEOUT: Duration = Duration::from_secs(30);
 pub const MAX_CHUNK_SIZE: usize = 20;
 pub const CACHE_BUFFER_SIZE: usize = 1000;
+/// How often a node gossips its peer list with every peer it knows of.
+pub const GOSSIP_INTERVAL: Duration = Duration::from_secs(5);
+/// How long to back off before retrying when every known peer looks dead.
+pub const PEER_RETRY_DELAY: Duration = Duration::from_secs(1);
 
-async fn services_impl(req: Request<Body>, db: SharedDB) -> Result<Response<Body>, hyper::Error> {
+async fn services_impl(
+    req: Request<Body>,
+    db: SharedDB,
+    peers: Peers,
+    metrics: Metrics,
+) -> Result<Response<Body>, hyper::Error> {
     match (req.method(), req.uri().path()) {
         (&Method::POST, "/insert") => {
+            metrics.record_insert();
             let modifs = deserialize::<Vec<EntryModif>>(&to_bytes(req.into_body()).await);
             db.append(modifs).await;
             Ok(Response::new(Body::default()))
         }
-        (&Method::GET, "/info") => Ok(Response::new(Body::from(
-            serde_json::to_string(&db.info().await).unwrap(),
-        ))),
+        (&Method::GET, "/info") => {
+            metrics.record_info();
+            Ok(Response::new(Body::from(
+                serde_json::to_string(&db.info().await).unwrap(),
+            )))
+        }
         (&Method::GET, "/fetch") => {
+            metrics.record_fetch();
             let (begin, end, head) =
                 deserialize::<(usize, usize, u32)>(&to_bytes(req.into_body()).await);
             Ok(Response::new(Body::from(
                 serde_json::to_string(&db.fetch(begin, end, head).await).unwrap(),
             )))
         }
+        (&Method::GET, "/watch") => {
+            let head = deserialize::<u32>(&to_bytes(req.into_body()).await);
+            Ok(Response::new(Body::from(
+                serde_json::to_string(&db.watch(head, WATCH_TIMEOUT).await).unwrap(),
+            )))
+        }
+        (&Method::GET, "/fetch_stream") => {
+            let (begin, end) = deserialize::<(usize, usize)>(&to_bytes(req.into_body()).await);
+            Ok(Response::new(Body::wrap_stream(
+                ndjson::NdjsonFetchBody::new(db, begin, end),
+            )))
+        }
+        (&Method::GET, "/merkle") => {
+            let path = deserialize(&to_bytes(req.into_body()).await);
+            Ok(Response::new(Body::from(
+                serde_json::to_string(&db.merkle_hash(path).await).unwrap(),
+            )))
+        }
+        (&Method::GET, "/merkle_range") => {
+            let path = deserialize(&to_bytes(req.into_body()).await);
+            Ok(Response::new(Body::from(
+                serde_json::to_string(&db.merkle_range(path).await).unwrap(),
+            )))
+        }
+        (&Method::GET, "/peers") => Ok(Response::new(Body::from(
+            serde_json::to_string(&peers.snapshot().await).unwrap(),
+        ))),
+        (&Method::GET, "/metrics") => {
+            let (head, data_size) = db.info().await;
+            let cache_len = db.cache_len().await;
+            Ok(Response::new(Body::from(
+                metrics.render(head, data_size, cache_len),
+            )))
+        }
         _ => {
             let mut not_found = Response::default();
             *not_found.status_mut() = StatusCode::NOT_FOUND;
@@ -39,35 +99,64 @@ async fn services_impl(req: Request<Body>, db: SharedDB) -> Result<Response<Body
     }
 }
 
+/// Builds the node's [SharedDB], backed by a disk-persistent [store::SledStore]
+/// when the `sled` feature is enabled (one sled database per port under
+/// `./data`), or the default in-memory [store::MemoryStore] otherwise.
+fn new_shared_db(node_id: u64, port: &str) -> SharedDB {
+    #[cfg(feature = "sled")]
+    {
+        let path = std::path::Path::new("data").join(port);
+        let store = store::SledStore::open(&path).expect("open sled store");
+        SharedDB::with_store(node_id, Box::new(store))
+    }
+    #[cfg(not(feature = "sled"))]
+    {
+        let _ = port;
+        SharedDB::new(node_id)
+    }
+}
+
 async fn shutdown_signal() {
     tokio::signal::ctrl_c()
         .await
         .expect("failed to install CTRL+C signal handler");
 }
 
-fn parse_input() -> (String, Option<String>) {
+fn parse_input() -> (String, Vec<String>) {
     let args: Vec<String> = std::env::args().collect();
     if args.len() == 2 {
-        (args[1].clone(), None)
+        (args[1].clone(), vec![])
     } else if args.len() == 3 {
-        (args[1].clone(), Some(args[2].clone()))
+        let seeds = args[2].split(',').map(str::to_string).collect();
+        (args[1].clone(), seeds)
     } else {
-        println!("error usage:\ncargo run -- {{port}} {{optional bootstrap port}}");
+        println!("error usage:\ncargo run -- {{port}} {{optional comma-separated seed ports}}");
         std::process::exit(1);
     }
 }
 
 #[tokio::main]
 async fn main() {
-    let (port, bootstrap_port) = parse_input();
-    let addr = SocketAddr::from(([127, 0, 0, 1], port.parse::<u16>().unwrap()));
-    let shared_database = SharedDB::default();
+    let (port, seed_ports) = parse_input();
+    let node_id = port.parse::<u16>().unwrap() as u64;
+    let addr = SocketAddr::from(([127, 0, 0, 1], node_id as u16));
+    let shared_database = new_shared_db(node_id, &port);
+    let seeds: Vec<SocketAddr> = seed_ports
+        .iter()
+        .map(|p| format!("127.0.0.1:{p}").parse().expect("invalid seed peer"))
+        .collect();
+    let peers = Peers::new(seeds);
+    let metrics = Metrics::default();
     let db = shared_database.clone();
+    let svc_peers = peers.clone();
+    let svc_metrics = metrics.clone();
     let make_svc = make_service_fn(move |_| {
         let db = db.clone();
+        let peers = svc_peers.clone();
+        let metrics = svc_metrics.clone();
         async move {
             Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                services_impl(req, db.clone())
+                services_impl(req, db.clone(), peers.clone(), metrics.clone())
             }))
         }
     });
@@ -77,14 +166,16 @@ async fn main() {
         "{}",
         serde_json::to_string(&EntryModif::Update((
             "key".to_string(),
-            "value".to_string()
+            "value".to_string(),
+            shared_database.timestamp().await
         )))
         .unwrap()
     );
     let server = Server::bind(&addr).serve(make_svc);
     let graceful = server.with_graceful_shutdown(shutdown_signal());
-    if let Some(p) = bootstrap_port {
-        spawn_fetch_loop(shared_database.clone(), format!("127.0.0.1:{p}"));
+    spawn_gossip_loop(peers.clone());
+    if !seed_ports.is_empty() {
+        spawn_fetch_loop(shared_database.clone(), peers, metrics);
     }
     if let Err(e) = graceful.await {
         eprintln!("server error: {}", e);
@@ -93,35 +184,110 @@ async fn main() {
     shared_database.dump().await;
 }
 
-fn spawn_fetch_loop(db: SharedDB, target: String) {
+/// Periodically merges peer lists learned from every known peer's `/peers`
+/// route into `peers`, so a node can learn about the rest of the cluster
+/// from whoever it happens to still be reachable through. Deliberately
+/// probes dead peers too, not just live ones: otherwise a peer marked dead
+/// can never be retried, and a transient blip that drops every known peer
+/// at once would strand this node in `best_peer`'s retry loop forever.
+fn spawn_gossip_loop(peers: Peers) {
     tokio::spawn(async move {
-        let client = hyper::client::Client::new();
-        let info = info_request(&client, &target).await;
-        let mut index = 0;
-        let end = info.1;
-        let mut head = info.0;
-        // todo, dump a progression status
-        while index < end {
-            let mut res = fetch_request(
-                &client,
-                &target,
-                index,
-                std::cmp::min(index + MAX_CHUNK_SIZE, end),
-                head,
-            )
-            .await;
-            head = res.head;
-            index += MAX_CHUNK_SIZE;
-            res.entries.append(&mut res.diff);
-            db.append(res.entries).await;
-            // you can check here, if for a while, we have no updates from the
-            // remote. And exit the loop
-            let s = tokio::time::sleep(BOOTSTRAP_FETCH_PERIOD);
-            tokio::pin!(s);
-            tokio::select! {
-                _ = s => continue,
+        let client = Client::new();
+        loop {
+            tokio::time::sleep(GOSSIP_INTERVAL).await;
+            for target in peers.snapshot().await {
+                match peers_request(&client, target).await {
+                    Some(learned) => {
+                        peers.mark_alive(target).await;
+                        peers.merge(learned).await;
+                    }
+                    None => peers.mark_dead(target).await,
+                }
+            }
+        }
+    });
+}
+
+/// Round-robins `/info` across every live peer, marking any that don't
+/// answer as dead, and returns the one with the highest `Head` to
+/// bootstrap from.
+async fn best_peer(
+    peers: &Peers,
+    client: &Client<HttpConnector>,
+) -> Option<(SocketAddr, u32, usize)> {
+    let mut best: Option<(SocketAddr, u32, usize)> = None;
+    for target in peers.live().await {
+        match info_request(client, target).await {
+            Some((head, size)) => {
+                if best.is_none_or(|(_, best_head, _)| head > best_head) {
+                    best = Some((target, head, size));
+                }
+            }
+            None => peers.mark_dead(target).await,
+        }
+    }
+    best
+}
+
+fn spawn_fetch_loop(db: SharedDB, peers: Peers, metrics: Metrics) {
+    tokio::spawn(async move {
+        let client = Client::new();
+        // Bootstrap: retry against whichever peer currently looks best until
+        // a full round completes end to end, failing over to another live
+        // peer - instead of panicking or leaving a silent gap - if the
+        // chosen target dies partway through. Safe to redo in full thanks to
+        // last-write-wins semantics.
+        let mut head = loop {
+            let Some((target, remote_head, end)) = best_peer(&peers, &client).await else {
+                tokio::time::sleep(PEER_RETRY_DELAY).await;
+                continue;
+            };
+            if db.info().await.1 > 0 {
+                // Already holds data from a previous run (restored from a
+                // persistent Store, or a Merkle round that already caught up
+                // most of the way): reconcile via the Merkle tree, which
+                // converges the whole dataset at leaf granularity, instead of
+                // unconditionally re-streaming everything below - that would
+                // turn the O(differences) reconcile into pure overhead on
+                // top of an O(N) copy that happens every single round.
+                if merkle_reconcile(&db, &client, target, vec![])
+                    .await
+                    .is_none()
+                {
+                    peers.mark_dead(target).await;
+                    continue;
+                }
+            } else if fetch_stream_request(&client, target, 0, end, &db, &metrics)
+                .await
+                .is_none()
+            {
+                // Progress is exposed live as `bootstrap_index`/`bootstrap_end`
+                // on `/metrics` rather than dumped to the logs.
+                peers.mark_dead(target).await;
+                continue;
+            }
+            break remote_head;
+        };
+        // Caught up with a snapshot: rather than sleeping on a fixed period
+        // and re-polling, long-poll /watch so new modifications are applied
+        // within milliseconds of being appended remotely, failing over to
+        // another live peer instead of panicking if the current one drops.
+        loop {
+            let Some(target) = peers.live().await.into_iter().next() else {
+                tokio::time::sleep(PEER_RETRY_DELAY).await;
+                continue;
+            };
+            let res = tokio::select! {
+                res = watch_request(&client, target, head) => res,
                 _ = tokio::signal::ctrl_c() => return,
             };
+            match res {
+                Some(res) => {
+                    head = res.head;
+                    db.append(res.diff).await;
+                }
+                None => peers.mark_dead(target).await,
+            }
         }
     });
 }
@@ -0,0 +1,87 @@
+//! Cluster membership: a gossiped set of known peers with basic liveness
+//! tracking, so the bootstrap and steady-state polling loops can fail over
+//! to another node instead of depending on one hardcoded source.
+use hyper::{client::HttpConnector, Client};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Whether the last request to a peer succeeded.
+#[derive(Clone, Copy)]
+struct PeerState {
+    alive: bool,
+}
+
+#[derive(Clone)]
+pub struct Peers(Arc<Mutex<HashMap<SocketAddr, PeerState>>>);
+
+impl Peers {
+    /// Start a membership list seeded with the peers given on the command
+    /// line, all assumed alive until proven otherwise.
+    pub fn new(seeds: Vec<SocketAddr>) -> Self {
+        let map = seeds
+            .into_iter()
+            .map(|addr| (addr, PeerState { alive: true }))
+            .collect();
+        Peers(Arc::new(Mutex::new(map)))
+    }
+
+    /// All known peers, alive or not: what the `/peers` route hands out
+    /// and what a gossip round merges into another node's set.
+    pub async fn snapshot(&self) -> Vec<SocketAddr> {
+        self.0.lock().await.keys().copied().collect()
+    }
+
+    /// Merge a peer list learned from another node's `/peers` response;
+    /// newly heard-of peers start out assumed alive.
+    pub async fn merge(&self, learned: Vec<SocketAddr>) {
+        let mut guard = self.0.lock().await;
+        for addr in learned {
+            guard.entry(addr).or_insert(PeerState { alive: true });
+        }
+    }
+
+    pub async fn mark_alive(&self, addr: SocketAddr) {
+        self.0
+            .lock()
+            .await
+            .entry(addr)
+            .or_insert(PeerState { alive: true })
+            .alive = true;
+    }
+
+    pub async fn mark_dead(&self, addr: SocketAddr) {
+        if let Some(state) = self.0.lock().await.get_mut(&addr) {
+            state.alive = false;
+        }
+    }
+
+    /// Currently-live peers, used to pick a bootstrap/watch target. The
+    /// gossip loop deliberately does *not* restrict itself to this set (see
+    /// [spawn_gossip_loop](crate::spawn_gossip_loop)): a peer marked dead
+    /// needs someone to keep probing it, or it can never come back.
+    pub async fn live(&self) -> Vec<SocketAddr> {
+        self.0
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, s)| s.alive)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+}
+
+/// Make a /peers request through the `client` to `target`, returning its
+/// known peer set, or `None` if it didn't answer.
+pub async fn peers_request(
+    client: &Client<HttpConnector>,
+    target: SocketAddr,
+) -> Option<Vec<SocketAddr>> {
+    let res = client
+        .get(format!("http://{target}/peers").parse().ok()?)
+        .await
+        .ok()?;
+    let bytes = hyper::body::to_bytes(res.into_body()).await.ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
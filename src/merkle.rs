@@ -0,0 +1,64 @@
+//! Hashing primitives for the Merkle anti-entropy tree used to reconcile
+//! two databases that have drifted further apart than
+//! [crate::CACHE_BUFFER_SIZE] allows [crate::shared_db::ModifsCache] to
+//! track: keys are bucketed by a fixed-depth prefix of their hash, and
+//! each bucket's entries are folded into a single range hash (see
+//! [crate::shared_db::SharedDB::merkle_hash]). Comparing hashes top-down
+//! lets a reconciling node skip whole subtrees that already match.
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Bits of a key's hash consumed per tree level: each node has
+/// `2^BITS_PER_LEVEL` children.
+pub const BITS_PER_LEVEL: u32 = 4;
+/// Number of levels below the root; the key space is partitioned into
+/// `ARITY.pow(TREE_DEPTH)` leaf ranges.
+pub const TREE_DEPTH: usize = 4;
+/// Number of children of any non-leaf node.
+pub const ARITY: u8 = 1 << BITS_PER_LEVEL;
+
+/// A path from the tree root to a node: one nibble of a key's hash per
+/// level crossed. The empty path denotes the root, which summarizes the
+/// whole database.
+pub type TreePath = Vec<u8>;
+
+pub fn hash_bytes(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Full root-to-leaf path for `key`: the successive nibbles of its hash.
+pub fn key_path(key: &str) -> TreePath {
+    let h = hash_bytes(key.as_bytes());
+    (0..TREE_DEPTH)
+        .map(|i| {
+            let shift = 64 - BITS_PER_LEVEL * (i as u32 + 1);
+            ((h >> shift) & (ARITY as u64 - 1)) as u8
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_path_has_one_nibble_per_tree_level() {
+        let path = key_path("some-key");
+        assert_eq!(path.len(), TREE_DEPTH);
+        assert!(path.iter().all(|&nibble| nibble < ARITY));
+    }
+
+    #[test]
+    fn key_path_is_deterministic() {
+        assert_eq!(key_path("some-key"), key_path("some-key"));
+    }
+
+    #[test]
+    fn different_keys_usually_diverge_before_the_leaf() {
+        let paths: std::collections::HashSet<_> =
+            (0..100).map(|i| key_path(&format!("key-{i}"))).collect();
+        assert!(paths.len() > 1);
+    }
+}
@@ -0,0 +1,77 @@
+//! Prometheus text-format observability for `/metrics`. Request counters
+//! live here as [Metrics]; gauges that mirror the live database (head,
+//! data size, cache buffer occupancy) are passed in by the caller at
+//! render time instead of this module depending on [crate::shared_db].
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[derive(Default)]
+struct Counters {
+    insert_requests: AtomicU64,
+    fetch_requests: AtomicU64,
+    info_requests: AtomicU64,
+    bootstrap_index: AtomicU64,
+    bootstrap_end: AtomicU64,
+}
+
+#[derive(Clone, Default)]
+pub struct Metrics(Arc<Counters>);
+
+impl Metrics {
+    pub fn record_insert(&self) {
+        self.0.insert_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_fetch(&self) {
+        self.0.fetch_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_info(&self) {
+        self.0.info_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Updated on every entry a bootstrap round applies, so `/metrics` can
+    /// expose how far a catch-up has gotten instead of that only being
+    /// visible by reading logs.
+    pub fn set_bootstrap_progress(&self, index: usize, end: usize) {
+        self.0
+            .bootstrap_index
+            .store(index as u64, Ordering::Relaxed);
+        self.0.bootstrap_end.store(end as u64, Ordering::Relaxed);
+    }
+
+    /// Render as Prometheus text format. `head`/`data_size`/`cache_len`
+    /// come from the caller's [crate::shared_db::SharedDB] snapshot.
+    pub fn render(&self, head: u32, data_size: usize, cache_len: usize) -> String {
+        let bootstrap_index = self.0.bootstrap_index.load(Ordering::Relaxed);
+        let bootstrap_end = self.0.bootstrap_end.load(Ordering::Relaxed);
+        let bootstrap_ratio = if bootstrap_end == 0 {
+            1.0
+        } else {
+            bootstrap_index as f64 / bootstrap_end as f64
+        };
+        format!(
+            "# TYPE insert_requests_total counter\n\
+             insert_requests_total {}\n\
+             # TYPE fetch_requests_total counter\n\
+             fetch_requests_total {}\n\
+             # TYPE info_requests_total counter\n\
+             info_requests_total {}\n\
+             # TYPE head gauge\n\
+             head {head}\n\
+             # TYPE data_size gauge\n\
+             data_size {data_size}\n\
+             # TYPE cache_buffer_len gauge\n\
+             cache_buffer_len {cache_len}\n\
+             # TYPE bootstrap_index gauge\n\
+             bootstrap_index {bootstrap_index}\n\
+             # TYPE bootstrap_end gauge\n\
+             bootstrap_end {bootstrap_end}\n\
+             # TYPE bootstrap_completion_ratio gauge\n\
+             bootstrap_completion_ratio {bootstrap_ratio}\n",
+            self.0.insert_requests.load(Ordering::Relaxed),
+            self.0.fetch_requests.load(Ordering::Relaxed),
+            self.0.info_requests.load(Ordering::Relaxed),
+        )
+    }
+}
@@ -0,0 +1,99 @@
+//! Streaming `/fetch_stream` response body: serializes one chunk of
+//! entries at a time as newline-delimited JSON (NDJSON) instead of
+//! buffering a whole [crate::shared_db::FetchResult] into a single
+//! `String` the way `/fetch` does, so a large range only ever holds one
+//! chunk in memory and a client can start applying entries before the
+//! whole range has arrived.
+//!
+//! A naive stream that held its `MutexGuard` across an `.await` wouldn't
+//! be `Sync`, which `Body::wrap_stream` requires (Garage hit this exact
+//! constraint reimplementing its own body type) - [NdjsonFetchBody]
+//! sidesteps it by only ever holding a pinned future that takes and drops
+//! the lock within a single chunk, so nothing guard-shaped crosses a
+//! `poll` boundary.
+use crate::shared_db::SharedDB;
+use hyper::body::{Bytes, HttpBody};
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+struct Cursor {
+    db: SharedDB,
+    index: usize,
+    end: usize,
+}
+
+type NextChunk = Pin<Box<dyn Future<Output = Option<(Bytes, Cursor)>> + Send>>;
+
+/// A [HttpBody] over `[begin, end)` that fetches and serializes one chunk
+/// at a time, each under its own short-lived lock acquisition.
+pub struct NdjsonFetchBody {
+    next: NextChunk,
+}
+
+impl NdjsonFetchBody {
+    pub fn new(db: SharedDB, begin: usize, end: usize) -> Self {
+        NdjsonFetchBody {
+            next: Box::pin(pull(Cursor {
+                db,
+                index: begin,
+                end,
+            })),
+        }
+    }
+}
+
+async fn pull(mut cursor: Cursor) -> Option<(Bytes, Cursor)> {
+    if cursor.index >= cursor.end {
+        return None;
+    }
+    let size = std::cmp::min(crate::MAX_CHUNK_SIZE, cursor.end - cursor.index);
+    let entries = cursor.db.fetch_chunk(cursor.index, size).await;
+    cursor.index += size;
+    let mut bytes = Vec::new();
+    for entry in entries {
+        serde_json::to_writer(&mut bytes, &entry).expect("serialize EntryModif");
+        bytes.push(b'\n');
+    }
+    Some((Bytes::from(bytes), cursor))
+}
+
+impl HttpBody for NdjsonFetchBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        match self.next.as_mut().poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Ready(Some((bytes, cursor))) => {
+                self.next = Box::pin(pull(cursor));
+                Poll::Ready(Some(Ok(bytes)))
+            }
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<hyper::HeaderMap>, Self::Error>> {
+        Poll::Ready(Ok(None))
+    }
+}
+
+/// `Body::wrap_stream` wants a `Stream`, not an `HttpBody` - this just
+/// forwards to [HttpBody::poll_data], whose signature already matches
+/// `Stream::poll_next` item for item.
+impl futures::Stream for NdjsonFetchBody {
+    type Item = Result<Bytes, Infallible>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        HttpBody::poll_data(self, cx)
+    }
+}
@@ -1,17 +1,47 @@
 use hyper::{client::HttpConnector, Body, Client, Method, Request};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{BTreeMap, VecDeque},
+    collections::{hash_map::DefaultHasher, VecDeque},
+    future::Future,
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    pin::Pin,
     sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex};
 
-use crate::{deserialize, to_bytes};
+use crate::merkle::{self, TreePath};
+use crate::metrics::Metrics;
+use crate::store::{MemoryStore, Store};
+
+/// Logical write timestamp: `(millis_since_epoch, node_id)` with
+/// lexicographic tie-break, so that two writes racing in the same
+/// millisecond still order deterministically across nodes.
+pub type Timestamp = (u64, u64);
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
 
 #[derive(Deserialize, Serialize, Clone)]
 pub enum EntryModif {
-    Delete(String),
-    Update((String, String)),
+    Delete((String, Timestamp)),
+    Update((String, String, Timestamp)),
+}
+
+/// A key's current value together with the timestamp of the write that
+/// produced it, so [DB::apply] can resolve concurrent writes with
+/// last-write-wins semantics. `value: None` is a tombstone: the key was
+/// deleted, but its timestamp is kept so an older `Update` replayed later
+/// (e.g. from another peer's cache) cannot resurrect it.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VersionedValue {
+    pub value: Option<String>,
+    pub timestamp: Timestamp,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -26,6 +56,15 @@ pub struct ModifsCache(VecDeque<(Head, Vec<EntryModif>)>);
 pub type Head = u32;
 
 impl ModifsCache {
+    /// Start a cache whose head is already at `head`, e.g. one restored
+    /// from a persistent [Store] on startup, so clients that already know
+    /// about `head` aren't handed a stale diff.
+    pub fn seeded(head: Head) -> Self {
+        let mut cache = VecDeque::new();
+        cache.push_front((head, vec![]));
+        ModifsCache(cache)
+    }
+
     /// Append new `EntryModif` batches into database,
     /// remove oldest values if buffer exceed [crate::CACHE_BUFFER_SIZE]
     pub fn append(&mut self, modifs: Vec<EntryModif>) {
@@ -45,6 +84,12 @@ impl ModifsCache {
         }
     }
 
+    /// Number of batches currently buffered, for `/metrics`' cache
+    /// occupancy gauge; saturates at [crate::CACHE_BUFFER_SIZE].
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
     /// Get a list of EntryModif between the give `head` and the current
     /// head of the SharedDB.
     pub fn diff(&self, head: u32) -> Vec<EntryModif> {
@@ -60,39 +105,109 @@ impl ModifsCache {
     }
 }
 
-#[derive(Default)]
 pub struct DB {
-    /// database, BTreeMap for index ordering
-    data: BTreeMap<String, String>,
+    /// Persistence backend; holds tombstones for deleted keys (see
+    /// [VersionedValue]) so a stale `Update` replayed later cannot
+    /// resurrect them.
+    store: Box<dyn Store>,
     /// Remember latest modifications for poll-bootstraper
     cache: ModifsCache,
+    /// Bumped by [SharedDB::append] to wake up parked `/watch` requests
+    watch_tx: watch::Sender<Head>,
+    /// Identifies this node's writes in a [Timestamp] tie-break
+    node_id: u64,
+}
+
+impl DB {
+    fn new(node_id: u64, store: Box<dyn Store>) -> Self {
+        let (watch_tx, _) = watch::channel(store.head());
+        DB {
+            cache: ModifsCache::seeded(store.head()),
+            store,
+            watch_tx,
+            node_id,
+        }
+    }
+
+    /// Apply a single incoming write with last-write-wins semantics:
+    /// only overwrites the stored entry when `incoming` is strictly newer,
+    /// so replaying the same modification from several peers in any order
+    /// converges to the same state.
+    fn apply(&mut self, key: String, incoming: VersionedValue) {
+        if let Some(existing) = self.store.get(&key) {
+            if existing.timestamp >= incoming.timestamp {
+                return;
+            }
+        }
+        self.store.insert(key, incoming);
+    }
 }
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
 pub struct SharedDB(Arc<Mutex<DB>>);
 
 impl SharedDB {
+    /// A node backed by the default in-memory store, which does not
+    /// survive a restart.
+    pub fn new(node_id: u64) -> Self {
+        Self::with_store(node_id, Box::<MemoryStore>::default())
+    }
+
+    /// A node backed by a custom [Store], e.g. a disk-backed one so it
+    /// resumes serving its existing data and head after a restart instead
+    /// of re-bootstrapping from scratch.
+    pub fn with_store(node_id: u64, store: Box<dyn Store>) -> Self {
+        SharedDB(Arc::new(Mutex::new(DB::new(node_id, store))))
+    }
+
     pub async fn append(&self, modifs: Vec<EntryModif>) {
         let mut guard = self.0.lock().await;
         for m in &modifs {
-            match m {
-                EntryModif::Delete(key) => guard.data.remove(key),
-                EntryModif::Update((key, val)) => guard.data.insert(key.clone(), val.clone()),
-            };
+            match m.clone() {
+                EntryModif::Delete((key, timestamp)) => guard.apply(
+                    key,
+                    VersionedValue {
+                        value: None,
+                        timestamp,
+                    },
+                ),
+                EntryModif::Update((key, val, timestamp)) => guard.apply(
+                    key,
+                    VersionedValue {
+                        value: Some(val),
+                        timestamp,
+                    },
+                ),
+            }
         }
         guard.cache.append(modifs);
+        let head = guard.cache.head();
+        guard.store.set_head(head);
+        guard.watch_tx.send_replace(head);
+    }
+
+    /// A `Timestamp` for a write happening now on this node.
+    pub async fn timestamp(&self) -> Timestamp {
+        (now_millis(), self.0.lock().await.node_id)
     }
 
-    /// Head and size of the db
+    /// Head and size of the db (includes tombstones, so that indices
+    /// handed out here line up with [take_chunk]'s iteration order)
     pub async fn info(&self) -> (u32, usize) {
         let guard = self.0.lock().await;
-        (guard.cache.head(), guard.data.len())
+        (guard.cache.head(), guard.store.len())
+    }
+
+    /// Number of modification batches currently buffered in the
+    /// [ModifsCache], for `/metrics`' cache occupancy gauge.
+    pub async fn cache_len(&self) -> usize {
+        self.0.lock().await.cache.len()
     }
 
     pub async fn fetch(&self, begin: usize, end: usize, head: u32) -> FetchResult {
         let guard = self.0.lock().await;
         let entries = take_chunk(
-            &guard.data,
+            guard.store.as_ref(),
             begin,
             std::cmp::min(crate::MAX_CHUNK_SIZE, end - begin),
         );
@@ -103,45 +218,286 @@ impl SharedDB {
         }
     }
 
+    /// Raw entries at `[begin, begin+size)`, without head/diff bookkeeping:
+    /// used by [crate::ndjson::NdjsonFetchBody] to pull one small chunk at
+    /// a time, each under its own short-lived lock, instead of
+    /// materializing a whole range at once like [SharedDB::fetch] does.
+    pub async fn fetch_chunk(&self, begin: usize, size: usize) -> Vec<EntryModif> {
+        let guard = self.0.lock().await;
+        take_chunk(guard.store.as_ref(), begin, size)
+    }
+
+    /// Long-poll for modifications past `head`: if the cache is already
+    /// ahead, returns immediately with the diff, otherwise parks until
+    /// `append` bumps the head or `timeout` elapses.
+    pub async fn watch(&self, head: Head, timeout: Duration) -> FetchResult {
+        let mut watch_rx = {
+            let guard = self.0.lock().await;
+            if guard.cache.head() != head {
+                return FetchResult {
+                    head: guard.cache.head(),
+                    entries: vec![],
+                    diff: guard.cache.diff(head),
+                };
+            }
+            guard.watch_tx.subscribe()
+        };
+        let sleep = tokio::time::sleep(timeout);
+        tokio::pin!(sleep);
+        tokio::select! {
+            _ = watch_rx.changed() => {}
+            _ = &mut sleep => {}
+        }
+        let guard = self.0.lock().await;
+        FetchResult {
+            head: guard.cache.head(),
+            entries: vec![],
+            diff: guard.cache.diff(head),
+        }
+    }
+
     pub async fn dump(&self) {
-        for (key, value) in self.0.lock().await.data.iter() {
-            println!("{key} - {value}");
+        for (key, value) in self.0.lock().await.store.iter() {
+            if let Some(value) = &value.value {
+                println!("{key} - {value}");
+            }
+        }
+    }
+
+    /// Hash of the Merkle tree node at `path` (see [crate::merkle]): the
+    /// empty path returns the root hash summarizing the whole database.
+    /// Tombstones are folded in like any other entry, so a node missing a
+    /// deletion still diverges from one that applied it.
+    pub async fn merkle_hash(&self, path: TreePath) -> u64 {
+        let guard = self.0.lock().await;
+        let mut hasher = DefaultHasher::new();
+        for (k, v) in guard
+            .store
+            .iter_matching(&|k| merkle::key_path(k).starts_with(&path))
+        {
+            merkle::hash_bytes(k.as_bytes()).hash(&mut hasher);
+            merkle::hash_bytes(v.value.as_deref().unwrap_or("").as_bytes()).hash(&mut hasher);
+            v.timestamp.hash(&mut hasher);
         }
+        hasher.finish()
+    }
+
+    /// Entries whose key falls in the Merkle range `path`, tombstones
+    /// included so a reconciling peer learns of deletions too.
+    pub async fn merkle_range(&self, path: TreePath) -> Vec<EntryModif> {
+        let guard = self.0.lock().await;
+        guard
+            .store
+            .iter_matching(&|k| merkle::key_path(k).starts_with(&path))
+            .into_iter()
+            .map(to_entry_modif)
+            .collect()
+    }
+}
+
+fn to_entry_modif((key, value): (String, VersionedValue)) -> EntryModif {
+    match value.value {
+        Some(val) => EntryModif::Update((key, val, value.timestamp)),
+        None => EntryModif::Delete((key, value.timestamp)),
     }
 }
 
-fn take_chunk(data: &BTreeMap<String, String>, from: usize, size: usize) -> Vec<EntryModif> {
-    data.iter()
-        .skip(from)
-        .take(size)
-        .map(|(k, v)| EntryModif::Update((k.clone(), v.clone())))
+fn take_chunk(store: &dyn Store, from: usize, size: usize) -> Vec<EntryModif> {
+    store
+        .range(from, size)
+        .into_iter()
+        .map(to_entry_modif)
         .collect()
 }
 
-/// Make a /info request through the `client` to the `target`
-/// Return a tuple (head, size) or panic if request fail
-pub async fn info_request(client: &Client<HttpConnector>, target: &String) -> (u32, usize) {
+/// Make a /info request through the `client` to the `target`, returning
+/// `(head, size)`, or `None` if the peer didn't answer so the caller can
+/// fail over to another one instead of panicking.
+pub async fn info_request(
+    client: &Client<HttpConnector>,
+    target: SocketAddr,
+) -> Option<(u32, usize)> {
     let res = client
-        .get(format!("http://{}/info", target).parse().unwrap())
+        .get(format!("http://{target}/info").parse().ok()?)
         .await
-        .unwrap();
-    deserialize(&to_bytes(res.into_body()).await)
+        .ok()?;
+    let bytes = hyper::body::to_bytes(res.into_body()).await.ok()?;
+    serde_json::from_slice(&bytes).ok()
 }
 
-pub async fn fetch_request(
+/// Make a /fetch_stream request through the `client` to the `target` and
+/// apply each NDJSON entry into `db` as it arrives off the wire, instead
+/// of buffering the whole range the way a single /fetch request would.
+/// Updates `metrics`' `bootstrap_index`/`bootstrap_end` gauges after every
+/// entry, so a long catch-up is observable by scraping `/metrics` rather
+/// than by reading logs. Returns `None` if the peer dropped out partway
+/// through, leaving `db` with whatever entries had already arrived - safe
+/// to retry thanks to last-write-wins semantics.
+pub async fn fetch_stream_request(
     client: &Client<HttpConnector>,
-    target: &String,
+    target: SocketAddr,
     begin: usize,
     end: usize,
-    head: u32,
-) -> FetchResult {
+    db: &SharedDB,
+    metrics: &Metrics,
+) -> Option<()> {
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(&format!("http://{target}/fetch_stream"))
+        .body(Body::from(serde_json::to_string(&(begin, end)).unwrap()))
+        .ok()?;
+    let mut body = client.request(req).await.ok()?.into_body();
+    let mut buf = Vec::new();
+    let mut index = begin;
+    metrics.set_bootstrap_progress(index, end);
+    while let Some(chunk) = hyper::body::HttpBody::data(&mut body).await {
+        buf.extend_from_slice(&chunk.ok()?);
+        while let Some(pos) = buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=pos).collect();
+            let modif: EntryModif = serde_json::from_slice(&line[..line.len() - 1]).ok()?;
+            db.append(vec![modif]).await;
+            index += 1;
+            metrics.set_bootstrap_progress(index, end);
+        }
+    }
+    Some(())
+}
+
+/// Make a /merkle request through the `client` to the `target`, returning
+/// the remote's Merkle tree node hash at `path`, or `None` if the peer
+/// didn't answer so [merkle_reconcile] can bail instead of panicking.
+pub async fn merkle_hash_request(
+    client: &Client<HttpConnector>,
+    target: SocketAddr,
+    path: &TreePath,
+) -> Option<u64> {
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(&format!("http://{target}/merkle"))
+        .body(Body::from(serde_json::to_string(path).unwrap()))
+        .ok()?;
+    let res = client.request(req).await.ok()?;
+    let bytes = hyper::body::to_bytes(res.into_body()).await.ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+/// Make a /merkle_range request through the `client` to the `target`,
+/// returning the remote's entries whose key falls in the range `path`, or
+/// `None` if the peer didn't answer.
+pub async fn merkle_range_request(
+    client: &Client<HttpConnector>,
+    target: SocketAddr,
+    path: &TreePath,
+) -> Option<Vec<EntryModif>> {
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(&format!("http://{target}/merkle_range"))
+        .body(Body::from(serde_json::to_string(path).unwrap()))
+        .ok()?;
+    let res = client.request(req).await.ok()?;
+    let bytes = hyper::body::to_bytes(res.into_body()).await.ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+/// Reconcile `db` against `target` starting from the Merkle tree node at
+/// `path`: compares root hashes and only recurses into subtrees that
+/// differ, finally pulling the exact keys of mismatching leaf ranges.
+/// This converges two databases that have drifted further apart than
+/// [crate::CACHE_BUFFER_SIZE], where [ModifsCache::diff] can no longer
+/// help, without blindly re-copying the whole dataset. Returns `None` as
+/// soon as `target` stops answering anywhere in the walk, instead of
+/// panicking, so the caller can pick another live peer with `best_peer`
+/// and retry the whole reconciliation.
+pub fn merkle_reconcile<'a>(
+    db: &'a SharedDB,
+    client: &'a Client<HttpConnector>,
+    target: SocketAddr,
+    path: TreePath,
+) -> Pin<Box<dyn Future<Output = Option<()>> + Send + 'a>> {
+    Box::pin(async move {
+        let remote_hash = merkle_hash_request(client, target, &path).await?;
+        let local_hash = db.merkle_hash(path.clone()).await;
+        if remote_hash == local_hash {
+            return Some(());
+        }
+        if path.len() == merkle::TREE_DEPTH {
+            let entries = merkle_range_request(client, target, &path).await?;
+            db.append(entries).await;
+            return Some(());
+        }
+        for child in 0..merkle::ARITY {
+            let mut child_path = path.clone();
+            child_path.push(child);
+            merkle_reconcile(db, client, target, child_path).await?;
+        }
+        Some(())
+    })
+}
+
+/// Make a /watch request through the `client` to the `target`, parking
+/// until the remote's head moves past `head` or its own timeout elapses.
+/// Returns `None` if the peer didn't answer, so the caller can fail over
+/// to another one instead of panicking.
+pub async fn watch_request(
+    client: &Client<HttpConnector>,
+    target: SocketAddr,
+    head: Head,
+) -> Option<FetchResult> {
     let req = Request::builder()
         .method(Method::GET)
-        .uri(&format!("http://{target}/fetch"))
-        .body(Body::from(
-            serde_json::to_string(&(begin, end, head)).unwrap(),
-        ))
-        .unwrap();
-    let res = client.request(req).await.unwrap();
-    deserialize(&to_bytes(res.into_body()).await)
+        .uri(&format!("http://{target}/watch"))
+        .body(Body::from(serde_json::to_string(&head).unwrap()))
+        .ok()?;
+    let res = client.request(req).await.ok()?;
+    let bytes = hyper::body::to_bytes(res.into_body()).await.ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn versioned(value: Option<&str>, timestamp: Timestamp) -> VersionedValue {
+        VersionedValue {
+            value: value.map(str::to_string),
+            timestamp,
+        }
+    }
+
+    fn db() -> DB {
+        DB::new(0, Box::<MemoryStore>::default())
+    }
+
+    #[test]
+    fn apply_keeps_the_newer_write() {
+        let mut db = db();
+        db.apply("k".to_string(), versioned(Some("v1"), (1, 0)));
+        db.apply("k".to_string(), versioned(Some("v2"), (2, 0)));
+        assert_eq!(db.store.get("k").unwrap().value, Some("v2".to_string()));
+    }
+
+    #[test]
+    fn apply_ignores_a_stale_replay() {
+        let mut db = db();
+        db.apply("k".to_string(), versioned(Some("v2"), (2, 0)));
+        db.apply("k".to_string(), versioned(Some("v1"), (1, 0)));
+        assert_eq!(db.store.get("k").unwrap().value, Some("v2".to_string()));
+    }
+
+    #[test]
+    fn equal_timestamps_do_not_overwrite() {
+        let mut db = db();
+        db.apply("k".to_string(), versioned(Some("v1"), (1, 0)));
+        db.apply("k".to_string(), versioned(Some("v2"), (1, 0)));
+        assert_eq!(db.store.get("k").unwrap().value, Some("v1".to_string()));
+    }
+
+    #[test]
+    fn tombstone_cannot_be_resurrected_by_a_stale_update() {
+        let mut db = db();
+        db.apply("k".to_string(), versioned(Some("v1"), (1, 0)));
+        db.apply("k".to_string(), versioned(None, (2, 0)));
+        db.apply("k".to_string(), versioned(Some("v1"), (1, 0)));
+        assert_eq!(db.store.get("k").unwrap().value, None);
+    }
 }
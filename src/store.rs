@@ -0,0 +1,192 @@
+//! Pluggable persistence for [crate::shared_db::DB]. [DB](crate::shared_db::DB)
+//! only ever talks to a `Box<dyn Store>`, so the in-memory map that used to
+//! be its only backend is now one adapter among others: a restart-durable
+//! one (`sled`, behind the `sled` feature) can replace it without touching
+//! `append`/`fetch`/`info`, letting a restarted node resume serving
+//! without re-bootstrapping.
+use crate::shared_db::{Head, VersionedValue};
+use std::collections::BTreeMap;
+
+pub trait Store: Send {
+    fn get(&self, key: &str) -> Option<VersionedValue>;
+    fn insert(&mut self, key: String, value: VersionedValue);
+    fn len(&self) -> usize;
+    /// Ordered entries starting at position `from`, up to `size` of them;
+    /// backs [crate::shared_db::take_chunk]'s seek. Implementations should
+    /// make this a real seek (e.g. an auxiliary position index) rather
+    /// than an `O(from)` walk from the start, since it's called once per
+    /// chunk of every bootstrap/catch-up range.
+    fn range(&self, from: usize, size: usize) -> Vec<(String, VersionedValue)>;
+    /// All entries, in key order.
+    fn iter(&self) -> Vec<(String, VersionedValue)>;
+    /// Entries in key order whose key matches `pred`, without paying to
+    /// clone or deserialize the ones that don't: backs [merkle
+    /// hashing](crate::shared_db::SharedDB::merkle_hash) and
+    /// [`/merkle_range`](crate::shared_db::SharedDB::merkle_range), which
+    /// only ever want the slice of the dataset under one Merkle path and
+    /// would otherwise pay for the whole store at every tree node visited.
+    fn iter_matching(&self, pred: &dyn Fn(&str) -> bool) -> Vec<(String, VersionedValue)>;
+    /// Last `Head` persisted by [Store::set_head], so a restarted node
+    /// resumes from where it left off instead of re-fetching everything.
+    fn head(&self) -> Head;
+    fn set_head(&mut self, head: Head);
+}
+
+/// Default backend: everything lives in a `BTreeMap` and is lost when the
+/// process exits, same as before the `Store` trait existed. `order` tracks
+/// the same keys the map would yield in iteration order, so [Store::range]
+/// can slice straight into position `from` instead of walking there -
+/// inserting a new key pays an `O(n)` shift instead, which this trades for
+/// since every bootstrap chunk exercises `range` but writes are rarer.
+#[derive(Default)]
+pub struct MemoryStore {
+    data: BTreeMap<String, VersionedValue>,
+    order: Vec<String>,
+    head: Head,
+}
+
+impl MemoryStore {
+    fn position_of(&self, key: &str) -> Result<usize, usize> {
+        self.order.binary_search_by(|k| k.as_str().cmp(key))
+    }
+}
+
+impl Store for MemoryStore {
+    fn get(&self, key: &str) -> Option<VersionedValue> {
+        self.data.get(key).cloned()
+    }
+
+    fn insert(&mut self, key: String, value: VersionedValue) {
+        if let Err(pos) = self.position_of(&key) {
+            self.order.insert(pos, key.clone());
+        }
+        self.data.insert(key, value);
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    fn range(&self, from: usize, size: usize) -> Vec<(String, VersionedValue)> {
+        self.order
+            .get(from..)
+            .unwrap_or_default()
+            .iter()
+            .take(size)
+            .map(|k| (k.clone(), self.data[k].clone()))
+            .collect()
+    }
+
+    fn iter(&self) -> Vec<(String, VersionedValue)> {
+        self.data
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    fn iter_matching(&self, pred: &dyn Fn(&str) -> bool) -> Vec<(String, VersionedValue)> {
+        self.data
+            .iter()
+            .filter(|(k, _)| pred(k))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    fn head(&self) -> Head {
+        self.head
+    }
+
+    fn set_head(&mut self, head: Head) {
+        self.head = head;
+    }
+}
+
+/// Disk-backed adapter: the map and its head survive a process restart, so
+/// a crash no longer means a full re-bootstrap (like Garage's sled-backed
+/// table storage).
+#[cfg(feature = "sled")]
+pub struct SledStore {
+    data: sled::Tree,
+    meta: sled::Tree,
+}
+
+#[cfg(feature = "sled")]
+impl SledStore {
+    pub fn open(path: &std::path::Path) -> sled::Result<Self> {
+        let db = sled::open(path)?;
+        Ok(SledStore {
+            data: db.open_tree("data")?,
+            meta: db.open_tree("meta")?,
+        })
+    }
+}
+
+#[cfg(feature = "sled")]
+impl Store for SledStore {
+    fn get(&self, key: &str) -> Option<VersionedValue> {
+        let bytes = self.data.get(key).expect("sled get")?;
+        Some(bincode::deserialize(&bytes).expect("corrupt sled entry"))
+    }
+
+    fn insert(&mut self, key: String, value: VersionedValue) {
+        let bytes = bincode::serialize(&value).expect("serialize VersionedValue");
+        self.data.insert(key, bytes).expect("sled insert");
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Unlike [MemoryStore::range], this is still an `O(from)` walk: sled's
+    /// `Tree` is a key-ordered B+tree with no positional index, so seeking
+    /// to an ordinal position means iterating past everything before it.
+    /// A real fix would need a persisted position-to-key index mirroring
+    /// [MemoryStore]'s `order` vec, kept in sync on every insert/remove;
+    /// not done here since this backend is feature-gated and unbuilt in
+    /// most environments that exercise this sample.
+    fn range(&self, from: usize, size: usize) -> Vec<(String, VersionedValue)> {
+        self.data
+            .iter()
+            .skip(from)
+            .take(size)
+            .map(|entry| {
+                let (key, bytes) = entry.expect("sled iter");
+                let key = String::from_utf8(key.to_vec()).expect("non-utf8 key");
+                let value = bincode::deserialize(&bytes).expect("corrupt sled entry");
+                (key, value)
+            })
+            .collect()
+    }
+
+    fn iter(&self) -> Vec<(String, VersionedValue)> {
+        self.range(0, self.len())
+    }
+
+    fn iter_matching(&self, pred: &dyn Fn(&str) -> bool) -> Vec<(String, VersionedValue)> {
+        self.data
+            .iter()
+            .filter_map(|entry| {
+                let (key, bytes) = entry.expect("sled iter");
+                let key = String::from_utf8(key.to_vec()).expect("non-utf8 key");
+                if !pred(&key) {
+                    return None;
+                }
+                let value = bincode::deserialize(&bytes).expect("corrupt sled entry");
+                Some((key, value))
+            })
+            .collect()
+    }
+
+    fn head(&self) -> Head {
+        match self.meta.get("head").expect("sled get") {
+            Some(bytes) => Head::from_be_bytes(bytes.as_ref().try_into().expect("corrupt head")),
+            None => 0,
+        }
+    }
+
+    fn set_head(&mut self, head: Head) {
+        self.meta
+            .insert("head", &head.to_be_bytes())
+            .expect("sled insert");
+    }
+}